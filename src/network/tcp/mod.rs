@@ -0,0 +1,2 @@
+pub mod negotiate;
+pub mod pipe;
@@ -0,0 +1,182 @@
+//! A single point-to-point connection: owns the transport-level stream,
+//! negotiates protocol/version over it (see `network::tcp::negotiate`)
+//! before ever raising `Event::Opened`, and turns stream readiness into
+//! the `Event`s a socket's `on_pipe_*` handlers react to.
+//!
+//! `Dispatcher::process_io` drives this with nothing more than the raw
+//! mio readiness for its token (`pipe.ready(el, &mut self.bus, events)`);
+//! `sid`/`eid` are carried on `Pipe` itself so it can address its own
+//! `Signal::PipeEvt`s rather than asking the caller to stitch them back
+//! together from context it doesn't have at that call site.
+
+use std::io::{self, Read, Write};
+use std::mem;
+
+use mio::{Poll, Token, Ready, PollOpt, Evented};
+
+use network::transport::Stream;
+use network::tcp::negotiate::{Negotiation, Role, Proposal, Outcome, NegotiationError};
+use network::endpoint::{SocketId, EndpointId};
+use reactors::event_loop::EventLoop;
+use reactors::api::Signal;
+use reactors::bus::EventLoopBus;
+
+// Single reads are capped at this; a frame larger than that just takes a
+// few more readiness ticks to drain instead of growing the buffer.
+const RECV_CHUNK: usize = 8 * 1024;
+
+pub enum Command {
+    Send(Vec<u8>),
+    Recv,
+    Close,
+}
+
+pub enum Event {
+    Opened,
+    CanSend,
+    CanRecv,
+    Sent,
+    Received(Vec<u8>),
+    Accepted(Vec<Pipe>),
+    Error(io::Error),
+    Closed,
+}
+
+enum State {
+    Negotiating(Negotiation),
+    Open,
+    Closed,
+}
+
+pub struct Pipe {
+    sid: SocketId,
+    eid: EndpointId,
+    stream: Box<Stream + Send>,
+    state: State,
+}
+
+impl Pipe {
+    /// Wraps an already-connected (or just-accepted) transport stream,
+    /// ready to negotiate. `role` is `Initiator` for a dialled pipe,
+    /// `Responder` for one handed back from `Acceptor::accept`.
+    pub fn new(sid: SocketId, eid: EndpointId, stream: Box<Stream + Send>, role: Role, local: Proposal) -> Pipe {
+        let negotiation = Negotiation::new(role, local, |_protocol_id, _version| true);
+
+        Pipe {
+            sid: sid,
+            eid: eid,
+            stream: stream,
+            state: State::Negotiating(negotiation),
+        }
+    }
+
+    /// Pumps whatever the current state needs in response to a readiness
+    /// event: drives the handshake to completion (or further along) while
+    /// `Negotiating`, otherwise turns the raw readiness into `CanSend`/
+    /// `CanRecv` for the socket layer to act on.
+    pub fn ready(&mut self, _el: &mut EventLoop, bus: &mut EventLoopBus<Signal>, events: Ready) {
+        if let State::Negotiating(_) = self.state {
+            self.drive_negotiation(bus);
+            return;
+        }
+
+        if let State::Closed = self.state {
+            return;
+        }
+
+        if events.is_readable() {
+            self.raise(bus, Event::CanRecv);
+        }
+        if events.is_writable() {
+            self.raise(bus, Event::CanSend);
+        }
+    }
+
+    /// Executes a command a socket handler issued against this pipe
+    /// (forwarded here via `Signal::PipeCmd`/`process_pipe_cmd`).
+    pub fn process(&mut self, el: &mut EventLoop, bus: &mut EventLoopBus<Signal>, cmd: Command) {
+        match cmd {
+            Command::Send(msg) => {
+                match self.stream.write(&msg) {
+                    Ok(_) => self.raise(bus, Event::Sent),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => self.fail(bus, e),
+                }
+            }
+            Command::Recv => {
+                let mut buf = [0u8; RECV_CHUNK];
+                match self.stream.read(&mut buf) {
+                    Ok(0) => self.raise(bus, Event::Closed),
+                    Ok(n) => self.raise(bus, Event::Received(buf[..n].to_vec())),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => self.fail(bus, e),
+                }
+            }
+            Command::Close => self.close(el),
+        }
+    }
+
+    pub fn close(&mut self, el: &mut EventLoop) {
+        let _ = el.deregister(&*self.stream);
+        self.state = State::Closed;
+    }
+
+    fn drive_negotiation(&mut self, bus: &mut EventLoopBus<Signal>) {
+        let state = mem::replace(&mut self.state, State::Closed);
+
+        let mut negotiation = match state {
+            State::Negotiating(negotiation) => negotiation,
+            other => {
+                self.state = other;
+                return;
+            }
+        };
+
+        match negotiation.drive(&mut *self.stream) {
+            Ok(Some(Outcome::Agreed { .. })) => {
+                self.state = State::Open;
+                self.raise(bus, Event::Opened);
+            }
+            Ok(Some(Outcome::Rejected(reason))) => {
+                self.state = State::Closed;
+                self.raise(bus, Event::Error(negotiation_failed(reason)));
+            }
+            Ok(None) => {
+                self.state = State::Negotiating(negotiation);
+            }
+            Err(e) => {
+                self.state = State::Closed;
+                self.raise(bus, Event::Error(e));
+            }
+        }
+    }
+
+    fn raise(&self, bus: &mut EventLoopBus<Signal>, evt: Event) {
+        bus.notifier().notify(Signal::PipeEvt(self.sid, self.eid, evt));
+    }
+
+    fn fail(&self, bus: &mut EventLoopBus<Signal>, err: io::Error) {
+        self.raise(bus, Event::Error(err));
+    }
+}
+
+fn negotiation_failed(reason: NegotiationError) -> io::Error {
+    let message = match reason {
+        NegotiationError::ProtocolMismatch => "peer proposed an unsupported protocol",
+        NegotiationError::VersionMismatch => "peer proposed an incompatible version",
+    };
+
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+impl Evented for Pipe {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.stream.register(poll, token, interest, opts)
+    }
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.stream.reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.stream.deregister(poll)
+    }
+}
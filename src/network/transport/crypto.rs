@@ -0,0 +1,742 @@
+//! An encrypted, authenticated decorator around any other `Transport`.
+//!
+//! The wire format and handshake are modelled on the RLPx framing used by
+//! the Ethereum devp2p wire protocol: both ends exchange a random nonce,
+//! mix it with a pre-shared transport secret to derive 64 bytes of key
+//! material, and split that into an AES-256-CTR session key and a MAC key.
+//! Every frame is length-prefixed, AES-CTR encrypted and tagged with a
+//! running Keccak MAC covering both the length header and the ciphertext,
+//! so a single compromised/garbled frame (or a flipped length byte) is
+//! detected before it ever reaches `pipe`.
+
+use std::io::{self, Read, Write, Error, ErrorKind};
+use std::mem;
+
+use mio::{Poll, Token, Ready, PollOpt, Evented};
+use crypto::aes::{self, KeySize};
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use tiny_keccak::Keccak;
+use rand::{self, Rng};
+
+use network::transport::{Transport, Stream, Listener};
+
+const NONCE_LEN: usize = 32;
+const MAC_LEN: usize = 16;
+const HEADER_LEN: usize = 4;
+// Generous but finite: bounds how much a peer can make us allocate for a
+// single frame body before we've even authenticated the length prefix.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Wraps an inner `Transport` so every stream it produces is encrypted and
+/// authenticated before `pipe` ever sees a byte of it. Selected per-URL via
+/// a scheme prefix, e.g. registering this under `tls+tcp://` while the
+/// plain transport stays registered under `tcp://`.
+pub struct CryptoTransport {
+    inner: Box<Transport + Send>,
+    psk: [u8; 32],
+}
+
+impl CryptoTransport {
+    pub fn new(inner: Box<Transport + Send>, psk: [u8; 32]) -> CryptoTransport {
+        CryptoTransport {
+            inner: inner,
+            psk: psk,
+        }
+    }
+}
+
+impl Transport for CryptoTransport {
+    fn connect(&self, url: &str) -> io::Result<Box<Stream + Send>> {
+        let inner = try!(self.inner.connect(url));
+
+        Ok(Box::new(CryptoStream::new(inner, self.psk)))
+    }
+
+    fn bind(&self, url: &str) -> io::Result<Box<Listener + Send>> {
+        let inner = try!(self.inner.bind(url));
+
+        Ok(Box::new(CryptoListener {
+            inner: inner,
+            psk: self.psk,
+        }))
+    }
+}
+
+struct CryptoListener {
+    inner: Box<Listener + Send>,
+    psk: [u8; 32],
+}
+
+impl Listener for CryptoListener {
+    fn accept(&self) -> io::Result<Box<Stream + Send>> {
+        let inner = try!(self.inner.accept());
+
+        Ok(Box::new(CryptoStream::new(inner, self.psk)))
+    }
+}
+
+impl Evented for CryptoListener {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)
+    }
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+/// Drives the handshake to completion the first time the underlying
+/// stream is readable/writable, then falls through to framed AES-CTR
+/// encode/decode. `pipe` only ever sees plaintext through `Read`/`Write`;
+/// it doesn't need to know the channel is encrypted at all, which is what
+/// lets `Dispatcher::process_io` keep emitting `pipe::Event::Opened` only
+/// once this returns real data instead of `WouldBlock`.
+///
+/// Both the handshake and the frame codec below are resumable: a
+/// `WouldBlock` from the inner stream partway through a nonce, header,
+/// body or tag leaves `state`/`recv`/`send` holding exactly how much has
+/// been consumed or produced so far, so the next readiness tick picks up
+/// from that byte instead of re-issuing a fresh read/write against a
+/// stream position it no longer agrees with.
+pub struct CryptoStream {
+    inner: Box<Stream + Send>,
+    psk: [u8; 32],
+    local_nonce: [u8; NONCE_LEN],
+    state: HandshakeState,
+    codec: Option<Codec>,
+    recv: RecvState,
+    send: SendState,
+    plaintext_in: Vec<u8>,
+}
+
+enum HandshakeState {
+    // Local nonce generated but not yet fully written out; `usize` is how
+    // much of it has gone out so far.
+    SendNonce([u8; NONCE_LEN], usize),
+    // Local nonce flushed, waiting to read the remote one.
+    RecvNonce([u8; NONCE_LEN], usize),
+    Done,
+}
+
+/// Progress reading the current inbound frame.
+enum RecvState {
+    Header { buf: [u8; HEADER_LEN], have: usize },
+    Body { header: [u8; HEADER_LEN], body: Vec<u8>, have: usize },
+    Tag { header: [u8; HEADER_LEN], body: Vec<u8>, tag: [u8; MAC_LEN], have: usize },
+}
+
+impl RecvState {
+    fn fresh() -> RecvState {
+        RecvState::Header { buf: [0u8; HEADER_LEN], have: 0 }
+    }
+}
+
+/// Progress writing the current outbound frame (header || ciphertext ||
+/// tag, already fully assembled once we leave `Idle`).
+enum SendState {
+    Idle,
+    Pending { framed: Vec<u8>, sent: usize, plaintext_len: usize },
+}
+
+/// Outcome of pumping the receive side once.
+enum Polled {
+    Frame(Vec<u8>),
+    Blocked,
+    Eof,
+}
+
+impl CryptoStream {
+    fn new(inner: Box<Stream + Send>, psk: [u8; 32]) -> CryptoStream {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        CryptoStream {
+            inner: inner,
+            psk: psk,
+            local_nonce: nonce,
+            state: HandshakeState::SendNonce(nonce, 0),
+            codec: None,
+            recv: RecvState::fresh(),
+            send: SendState::Idle,
+            plaintext_in: Vec::new(),
+        }
+    }
+
+    /// Pumps the handshake forward by as much as the underlying stream
+    /// allows right now. Returns `Ok(true)` once the secure channel is
+    /// established, `Ok(false)` if it would block waiting on more I/O, and
+    /// `Err` on a hard I/O failure or the peer closing mid-handshake (the
+    /// caller surfaces it as `pipe::Event::Error`).
+    fn drive_handshake(&mut self) -> io::Result<bool> {
+        loop {
+            let state = mem::replace(&mut self.state, HandshakeState::Done);
+
+            match state {
+                HandshakeState::SendNonce(nonce, sent) => {
+                    match self.inner.write(&nonce[sent..]) {
+                        Ok(n) if sent + n == NONCE_LEN => {
+                            self.state = HandshakeState::RecvNonce([0u8; NONCE_LEN], 0);
+                        }
+                        Ok(n) => {
+                            // Partial write of a 32 byte nonce on a fresh
+                            // socket essentially never happens in practice;
+                            // remember how much went out and resume from
+                            // there on the next tick.
+                            self.state = HandshakeState::SendNonce(nonce, sent + n);
+                            return Ok(false);
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            self.state = HandshakeState::SendNonce(nonce, sent);
+                            return Ok(false);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                HandshakeState::RecvNonce(mut buf, mut have) => {
+                    match self.inner.read(&mut buf[have..]) {
+                        Ok(0) => {
+                            return Err(Error::new(ErrorKind::UnexpectedEof,
+                                                   "peer closed during handshake"));
+                        }
+                        Ok(n) => {
+                            have += n;
+
+                            if have < NONCE_LEN {
+                                self.state = HandshakeState::RecvNonce(buf, have);
+                                continue;
+                            }
+
+                            self.codec = Some(Codec::new(self.psk, &self.local_nonce, &buf));
+                            self.state = HandshakeState::Done;
+                            return Ok(true);
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            self.state = HandshakeState::RecvNonce(buf, have);
+                            return Ok(false);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                HandshakeState::Done => {
+                    self.state = HandshakeState::Done;
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Pumps the current inbound frame forward by as much as the
+    /// underlying stream allows right now, authenticating the header
+    /// together with the body before ever trusting either.
+    fn pump_recv(&mut self) -> io::Result<Polled> {
+        loop {
+            let state = mem::replace(&mut self.recv, RecvState::fresh());
+
+            match state {
+                RecvState::Header { mut buf, mut have } => {
+                    match self.inner.read(&mut buf[have..]) {
+                        Ok(0) => {
+                            if have == 0 {
+                                self.recv = RecvState::Header { buf: buf, have: have };
+                                return Ok(Polled::Eof);
+                            }
+                            return Err(Error::new(ErrorKind::UnexpectedEof,
+                                                   "peer closed mid-frame"));
+                        }
+                        Ok(n) => {
+                            have += n;
+
+                            if have < HEADER_LEN {
+                                self.recv = RecvState::Header { buf: buf, have: have };
+                                continue;
+                            }
+
+                            let len = ((buf[0] as usize) << 24) | ((buf[1] as usize) << 16) |
+                                      ((buf[2] as usize) << 8) | (buf[3] as usize);
+
+                            if len > MAX_FRAME_LEN {
+                                return Err(Error::new(ErrorKind::InvalidData,
+                                                       "frame exceeds maximum length"));
+                            }
+
+                            self.recv = RecvState::Body {
+                                header: buf,
+                                body: vec![0u8; len],
+                                have: 0,
+                            };
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            self.recv = RecvState::Header { buf: buf, have: have };
+                            return Ok(Polled::Blocked);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                RecvState::Body { header, body, have } => {
+                    if body.is_empty() {
+                        self.recv = RecvState::Tag {
+                            header: header,
+                            body: body,
+                            tag: [0u8; MAC_LEN],
+                            have: 0,
+                        };
+                        continue;
+                    }
+
+                    let mut body = body;
+                    let mut have = have;
+
+                    match self.inner.read(&mut body[have..]) {
+                        Ok(0) => {
+                            return Err(Error::new(ErrorKind::UnexpectedEof,
+                                                   "peer closed mid-frame"));
+                        }
+                        Ok(n) => {
+                            have += n;
+
+                            if have < body.len() {
+                                self.recv = RecvState::Body { header: header, body: body, have: have };
+                                continue;
+                            }
+
+                            self.recv = RecvState::Tag {
+                                header: header,
+                                body: body,
+                                tag: [0u8; MAC_LEN],
+                                have: 0,
+                            };
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            self.recv = RecvState::Body { header: header, body: body, have: have };
+                            return Ok(Polled::Blocked);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                RecvState::Tag { header, body, mut tag, mut have } => {
+                    match self.inner.read(&mut tag[have..]) {
+                        Ok(0) => {
+                            return Err(Error::new(ErrorKind::UnexpectedEof,
+                                                   "peer closed mid-frame"));
+                        }
+                        Ok(n) => {
+                            have += n;
+
+                            if have < MAC_LEN {
+                                self.recv = RecvState::Tag {
+                                    header: header,
+                                    body: body,
+                                    tag: tag,
+                                    have: have,
+                                };
+                                continue;
+                            }
+
+                            let codec = self.codec.as_mut().unwrap();
+                            if !codec.check_ingress(&header, &body, &tag) {
+                                return Err(Error::new(ErrorKind::InvalidData,
+                                                       "ingress MAC mismatch"));
+                            }
+
+                            let mut plain = body;
+                            codec.decrypt(&mut plain);
+
+                            self.recv = RecvState::fresh();
+                            return Ok(Polled::Frame(plain));
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            self.recv = RecvState::Tag {
+                                header: header,
+                                body: body,
+                                tag: tag,
+                                have: have,
+                            };
+                            return Ok(Polled::Blocked);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drains as much of the pending outbound frame (if any) as the
+    /// underlying stream accepts right now. Returns `true` once nothing
+    /// is left pending.
+    fn flush_pending(&mut self) -> io::Result<bool> {
+        let done = match self.send {
+            SendState::Idle => true,
+            SendState::Pending { ref framed, ref mut sent, .. } => {
+                loop {
+                    if *sent == framed.len() {
+                        break true;
+                    }
+
+                    match self.inner.write(&framed[*sent..]) {
+                        Ok(0) => {
+                            return Err(Error::new(ErrorKind::WriteZero,
+                                                   "inner stream accepted zero bytes"));
+                        }
+                        Ok(n) => *sent += n,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break false,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        };
+
+        if done {
+            self.send = SendState::Idle;
+        }
+
+        Ok(done)
+    }
+}
+
+impl Read for CryptoStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.codec.is_none() {
+            if !try!(self.drive_handshake()) {
+                return Err(Error::new(ErrorKind::WouldBlock, "handshake in progress"));
+            }
+        }
+
+        if !self.plaintext_in.is_empty() {
+            let n = ::std::cmp::min(buf.len(), self.plaintext_in.len());
+            buf[..n].copy_from_slice(&self.plaintext_in[..n]);
+            self.plaintext_in.drain(..n);
+            return Ok(n);
+        }
+
+        match try!(self.pump_recv()) {
+            Polled::Frame(plain) => {
+                let n = ::std::cmp::min(buf.len(), plain.len());
+                buf[..n].copy_from_slice(&plain[..n]);
+                if n < plain.len() {
+                    self.plaintext_in.extend_from_slice(&plain[n..]);
+                }
+                Ok(n)
+            }
+            Polled::Blocked => Err(Error::new(ErrorKind::WouldBlock, "frame not fully received")),
+            Polled::Eof => Ok(0),
+        }
+    }
+}
+
+impl Write for CryptoStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.codec.is_none() {
+            if !try!(self.drive_handshake()) {
+                return Err(Error::new(ErrorKind::WouldBlock, "handshake in progress"));
+            }
+        }
+
+        if let SendState::Idle = self.send {
+            if buf.len() > MAX_FRAME_LEN {
+                return Err(Error::new(ErrorKind::InvalidInput, "frame too large"));
+            }
+
+            let mut ciphertext = buf.to_vec();
+            let codec = self.codec.as_mut().unwrap();
+            codec.encrypt(&mut ciphertext);
+
+            let len = ciphertext.len() as u32;
+            let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+            let tag = codec.tag_egress(&header, &ciphertext);
+
+            let mut framed = Vec::with_capacity(HEADER_LEN + ciphertext.len() + MAC_LEN);
+            framed.extend_from_slice(&header);
+            framed.extend_from_slice(&ciphertext);
+            framed.extend_from_slice(&tag);
+
+            self.send = SendState::Pending {
+                framed: framed,
+                sent: 0,
+                plaintext_len: buf.len(),
+            };
+        }
+
+        // Once a frame is pending, a caller is expected to keep retrying
+        // with the same `buf` on `WouldBlock` (the normal non-blocking
+        // `Write` contract) until we report it fully accepted below; we
+        // never re-encrypt it, since the AES-CTR keystream has already
+        // moved on.
+        let plaintext_len = match self.send {
+            SendState::Pending { plaintext_len, .. } => plaintext_len,
+            SendState::Idle => unreachable!(),
+        };
+
+        if try!(self.flush_pending()) {
+            Ok(plaintext_len)
+        } else {
+            Err(Error::new(ErrorKind::WouldBlock, "frame partially sent"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.flush_pending());
+        self.inner.flush()
+    }
+}
+
+impl Evented for CryptoStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.inner.register(poll, token, interest, opts)
+    }
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.inner.reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.inner.deregister(poll)
+    }
+}
+
+/// Holds the derived session/MAC keys and the two independent running MAC
+/// states once the handshake has completed.
+struct Codec {
+    encryptor: Box<SynchronousStreamCipher>,
+    decryptor: Box<SynchronousStreamCipher>,
+    egress_mac: Keccak,
+    ingress_mac: Keccak,
+}
+
+impl Codec {
+    fn new(psk: [u8; 32], local_nonce: &[u8; NONCE_LEN], remote_nonce: &[u8; NONCE_LEN]) -> Codec {
+        // In the real RLPx handshake this mixes in an ECDHE shared
+        // secret; here the two sides authenticate with a configured
+        // pre-shared transport secret instead, which is enough to give
+        // callers integrity/confidentiality over an untrusted network
+        // without standing up a full PKI. Nonces are folded in under a
+        // fixed ordering so both ends derive identical key material
+        // regardless of who dialed and who listened.
+        let (first, second) = if local_nonce[..] < remote_nonce[..] {
+            (local_nonce, remote_nonce)
+        } else {
+            (remote_nonce, local_nonce)
+        };
+
+        let mut seed = Keccak::new_keccak512();
+        seed.update(&psk);
+        seed.update(first);
+        seed.update(second);
+        let mut key_material = [0u8; 64];
+        seed.finalize(&mut key_material);
+
+        let session_key = &key_material[0..32];
+        let mac_key = &key_material[32..64];
+
+        // A single `session_key` shared by both directions would mean
+        // both peers' AES-CTR keystreams are identical (same key, same
+        // all-zero IV), so a frame we send and a frame we receive would
+        // be encrypted with the same keystream bytes — XOR-ing the two
+        // ciphertexts then cancels the key entirely and leaks the XOR of
+        // the two plaintexts (a two-time pad). Split into two distinct
+        // per-direction keys the same way the MAC keys already split by
+        // nonce below, so encrypting and decrypting never reuse a
+        // keystream.
+        let encrypt_key = derive_direction_key(session_key, remote_nonce);
+        let decrypt_key = derive_direction_key(session_key, local_nonce);
+
+        let iv = [0u8; 16];
+        let encryptor = aes::ctr(KeySize::KeySize256, &encrypt_key, &iv);
+        let decryptor = aes::ctr(KeySize::KeySize256, &decrypt_key, &iv);
+
+        // `egress_mac` authenticates what *we* send, seeded with
+        // `mac_key ^ remote_nonce`; the peer seeds their `ingress_mac`
+        // with `mac_key ^ their-own-nonce` (the same bytes, since their
+        // nonce is the `remote_nonce` we just folded in), so both sides
+        // converge on identical running digests without ever exchanging
+        // a MAC state directly.
+        let mut egress_mac = Keccak::new_keccak256();
+        egress_mac.update(&xor32(mac_key, remote_nonce));
+
+        let mut ingress_mac = Keccak::new_keccak256();
+        ingress_mac.update(&xor32(mac_key, local_nonce));
+
+        Codec {
+            encryptor: encryptor,
+            decryptor: decryptor,
+            egress_mac: egress_mac,
+            ingress_mac: ingress_mac,
+        }
+    }
+
+    fn encrypt(&mut self, data: &mut [u8]) {
+        let input = data.to_vec();
+        self.encryptor.process(&input, data);
+    }
+
+    fn decrypt(&mut self, data: &mut [u8]) {
+        let input = data.to_vec();
+        self.decryptor.process(&input, data);
+    }
+
+    /// Tags `header || ciphertext` together so a frame's length prefix is
+    /// authenticated exactly like its body; flipping either without the
+    /// matching MAC key fails verification the same way.
+    fn tag_egress(&mut self, header: &[u8], ciphertext: &[u8]) -> [u8; MAC_LEN] {
+        self.egress_mac.update(header);
+        self.egress_mac.update(ciphertext);
+        digest_prefix(&self.egress_mac)
+    }
+
+    fn check_ingress(&mut self, header: &[u8], ciphertext: &[u8], tag: &[u8]) -> bool {
+        self.ingress_mac.update(header);
+        self.ingress_mac.update(ciphertext);
+        let expected = digest_prefix(&self.ingress_mac);
+        constant_time_eq(&expected, tag)
+    }
+}
+
+/// Hashes `session_key` mixed with `nonce` into a fresh 32-byte AES key,
+/// so that seeding with the peer's nonce (for what we encrypt) versus our
+/// own nonce (for what we decrypt) yields two unrelated keys instead of
+/// reusing `session_key` directly for both directions.
+fn derive_direction_key(session_key: &[u8], nonce: &[u8; NONCE_LEN]) -> [u8; 32] {
+    let mixed = xor32(session_key, nonce);
+    let mut hash = Keccak::new_keccak256();
+    hash.update(&mixed);
+    let mut out = [0u8; 32];
+    hash.finalize(&mut out);
+    out
+}
+
+/// Compares two MAC tags without branching on the first differing byte,
+/// so an on-path attacker probing forged tags can't use response timing
+/// to learn which byte they got wrong.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut diff = 0u8;
+    for i in 0..a.len() {
+        diff |= a[i] ^ b[i];
+    }
+    diff == 0
+}
+
+fn digest_prefix(mac: &Keccak) -> [u8; MAC_LEN] {
+    // Peek the running digest without consuming the MAC state, mirroring
+    // how RLPx's frame-mac lets every frame authenticate the whole
+    // session so far instead of itself in isolation.
+    let mut full = [0u8; 32];
+    mac.clone().finalize(&mut full);
+    let mut out = [0u8; MAC_LEN];
+    out.copy_from_slice(&full[..MAC_LEN]);
+    out
+}
+
+fn xor32(a: &[u8], b: &[u8]) -> [u8; NONCE_LEN] {
+    let mut out = [0u8; NONCE_LEN];
+    for i in 0..NONCE_LEN {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_codecs() -> (Codec, Codec) {
+        let psk = [7u8; 32];
+        let nonce_a = [1u8; NONCE_LEN];
+        let nonce_b = [2u8; NONCE_LEN];
+
+        (Codec::new(psk, &nonce_a, &nonce_b), Codec::new(psk, &nonce_b, &nonce_a))
+    }
+
+    #[test]
+    fn encrypt_then_decrypt_round_trips() {
+        let (mut a, mut b) = sample_codecs();
+
+        let mut ciphertext = b"hello crate".to_vec();
+        a.encrypt(&mut ciphertext);
+
+        let mut plain = ciphertext.clone();
+        b.decrypt(&mut plain);
+
+        assert_eq!(&plain[..], &b"hello crate"[..]);
+    }
+
+    #[test]
+    fn the_two_directions_do_not_share_a_keystream() {
+        // Regression test for a two-time-pad break: if both directions
+        // ever reused the same AES-CTR keystream, XOR-ing what `a` sends
+        // with what `b` sends would reproduce the XOR of the two
+        // plaintexts with no key material needed at all.
+        let (mut a, mut b) = sample_codecs();
+
+        let p1 = b"plaintext one";
+        let p2 = b"plaintext two";
+
+        let mut ct_a = p1.to_vec();
+        a.encrypt(&mut ct_a);
+
+        let mut ct_b = p2.to_vec();
+        b.encrypt(&mut ct_b);
+
+        let mut xor_plain = [0u8; 13];
+        let mut xor_cipher = [0u8; 13];
+        for i in 0..13 {
+            xor_plain[i] = p1[i] ^ p2[i];
+            xor_cipher[i] = ct_a[i] ^ ct_b[i];
+        }
+
+        assert_ne!(&xor_cipher[..], &xor_plain[..]);
+    }
+
+    #[test]
+    fn direction_keys_differ_so_each_side_gets_its_own_keystream() {
+        let session_key = [9u8; 32];
+        let nonce_a = [1u8; NONCE_LEN];
+        let nonce_b = [2u8; NONCE_LEN];
+
+        let key_ab = derive_direction_key(&session_key, &nonce_b);
+        let key_ba = derive_direction_key(&session_key, &nonce_a);
+
+        assert_ne!(&key_ab[..], &key_ba[..]);
+    }
+
+    #[test]
+    fn ingress_mac_accepts_the_matching_egress_tag() {
+        let (mut a, mut b) = sample_codecs();
+
+        let header = [0u8, 0, 0, 5];
+        let mut ciphertext = b"abcde".to_vec();
+        a.encrypt(&mut ciphertext);
+        let tag = a.tag_egress(&header, &ciphertext);
+
+        assert!(b.check_ingress(&header, &ciphertext, &tag));
+    }
+
+    #[test]
+    fn ingress_mac_rejects_a_tampered_header() {
+        let (mut a, mut b) = sample_codecs();
+
+        let header = [0u8, 0, 0, 5];
+        let mut ciphertext = b"abcde".to_vec();
+        a.encrypt(&mut ciphertext);
+        let tag = a.tag_egress(&header, &ciphertext);
+
+        let tampered_header = [0u8, 0, 0, 6];
+        assert!(!b.check_ingress(&tampered_header, &ciphertext, &tag));
+    }
+
+    #[test]
+    fn ingress_mac_rejects_a_tampered_body() {
+        let (mut a, mut b) = sample_codecs();
+
+        let header = [0u8, 0, 0, 5];
+        let mut ciphertext = b"abcde".to_vec();
+        a.encrypt(&mut ciphertext);
+        let tag = a.tag_egress(&header, &ciphertext);
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 0xff;
+        assert!(!b.check_ingress(&header, &tampered, &tag));
+    }
+}
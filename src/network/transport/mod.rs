@@ -0,0 +1,24 @@
+use std::io::{self, Read, Write};
+
+use mio::Evented;
+
+pub mod crypto;
+
+/// A transport turns a URL body (the part after `scheme://`) into a
+/// byte-oriented, non-blocking stream or listener. `Dispatcher` keeps one
+/// boxed `Transport` per registered scheme and hands `connect`/`bind`
+/// requests to whichever one matches.
+pub trait Transport {
+    fn connect(&self, url: &str) -> io::Result<Box<Stream + Send>>;
+    fn bind(&self, url: &str) -> io::Result<Box<Listener + Send>>;
+}
+
+/// A single non-blocking duplex connection, pollable through mio.
+pub trait Stream: Read + Write + Evented {}
+
+impl<T> Stream for T where T: Read + Write + Evented {}
+
+/// A listening endpoint that yields freshly accepted `Stream`s.
+pub trait Listener: Evented {
+    fn accept(&self) -> io::Result<Box<Stream + Send>>;
+}
@@ -0,0 +1,42 @@
+//! The message types that flow into a running `Dispatcher`: `Request`
+//! arrives over its `mio::channel::Receiver` (one hop from the public
+//! session/socket handles), `Signal` is what `pipe`/`acceptor`/`socket`
+//! push back onto its `EventLoopBus` to report their own events, and
+//! `Task` is what the dispatcher's own `Timer` redelivers once a
+//! scheduled delay elapses.
+
+use std::time::Duration;
+
+use network::endpoint::{SocketId, DeviceId, EndpointId};
+use network::tcp::pipe;
+use network::{session, socket, endpoint, device};
+use reactors::dispatcher::Schedulable;
+
+pub enum Request {
+    Session(session::Request),
+    Socket(SocketId, socket::Request),
+    Endpoint(SocketId, EndpointId, endpoint::Request),
+    Device(DeviceId, device::Request),
+}
+
+pub enum Signal {
+    PipeCmd(SocketId, EndpointId, pipe::Command),
+    AcceptorCmd(SocketId, EndpointId, pipe::Command),
+    PipeEvt(SocketId, EndpointId, pipe::Event),
+    AcceptorEvt(SocketId, EndpointId, pipe::Event),
+    SocketEvt(SocketId, pipe::Event),
+    /// Requests a graceful, draining shutdown of the whole dispatcher.
+    /// Raised by `reactors::signals::install`'s SIGINT handler, the only
+    /// caller that genuinely has nothing but a `Send`-only
+    /// `bus.notifier()` to push through (a signal handler runs on its own
+    /// OS thread, never the dispatcher's). Anything that already talks to
+    /// the dispatcher over `channel` — i.e. any embedder — should reach
+    /// this through `session::Request::ShutdownGraceful` instead. The
+    /// `Duration` is how long to wait for in-flight sends to flush before
+    /// closing everything anyway.
+    Shutdown(Duration),
+}
+
+pub enum Task {
+    Socket(SocketId, Schedulable),
+}
@@ -0,0 +1,221 @@
+//! A cooperative, stackful-coroutine execution model for socket handlers,
+//! alongside the existing callback-driven `on_*` dispatch. Modelled on
+//! ARTIQ's `sched.rs`: a handler runs as an ordinary-looking thread of
+//! control built on a libfringe `Generator`, and can block on a condition
+//! with an optional deadline instead of the protocol code itself juggling
+//! `Schedulable::SendTimeout`/`RecvTimeout` by hand.
+
+use std::time::{Duration, Instant};
+
+use fringe::{Generator, OwnedStack};
+use fringe::generator::Yielder;
+
+const STACK_SIZE: usize = 256 * 1024;
+
+/// What a parked handler is waiting on before it can be resumed.
+pub struct WaitRequest {
+    pub predicate: Option<Box<Fn() -> bool + Send>>,
+    pub timeout: Option<Duration>,
+}
+
+impl WaitRequest {
+    pub fn until<F>(predicate: F) -> WaitRequest
+        where F: Fn() -> bool + Send + 'static
+    {
+        WaitRequest {
+            predicate: Some(Box::new(predicate)),
+            timeout: None,
+        }
+    }
+
+    pub fn sleep(d: Duration) -> WaitRequest {
+        WaitRequest {
+            predicate: None,
+            timeout: Some(d),
+        }
+    }
+
+    pub fn until_or_timeout<F>(predicate: F, d: Duration) -> WaitRequest
+        where F: Fn() -> bool + Send + 'static
+    {
+        WaitRequest {
+            predicate: Some(Box::new(predicate)),
+            timeout: Some(d),
+        }
+    }
+}
+
+/// Why a parked handler was resumed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WaitResult {
+    Completed,
+    TimedOut,
+    Interrupted,
+}
+
+/// A handler thread of control, suspended at a `WaitRequest` or already
+/// finished. Lives inside `Dispatcher`'s parked list between the point it
+/// yields and the point its predicate or deadline is satisfied.
+pub struct Handler {
+    generator: Generator<'static, WaitResult, WaitRequest, OwnedStack>,
+    wait: Option<WaitRequest>,
+    deadline: Option<Instant>,
+    finished: bool,
+}
+
+impl Handler {
+    /// Spawns a handler thread running `body` on its own stack, driving it
+    /// to its first `yield` (or straight to completion, if it never
+    /// blocks).
+    pub fn spawn<F>(body: F) -> Handler
+        where F: FnOnce(&Yielder<WaitResult, WaitRequest>) + Send + 'static
+    {
+        let stack = OwnedStack::new(STACK_SIZE);
+        let mut generator = Generator::new(stack, move |yielder, _: WaitResult| body(yielder));
+
+        let first = generator.resume(WaitResult::Completed);
+
+        let mut handler = Handler {
+            generator: generator,
+            wait: None,
+            deadline: None,
+            finished: false,
+        };
+        handler.absorb(first);
+        handler
+    }
+
+    fn absorb(&mut self, yielded: Option<WaitRequest>) {
+        match yielded {
+            Some(wait) => {
+                self.deadline = wait.timeout.map(|d| Instant::now() + d);
+                self.wait = Some(wait);
+            }
+            None => {
+                self.wait = None;
+                self.deadline = None;
+                self.finished = true;
+            }
+        }
+    }
+
+    /// Still parked, i.e. hasn't run to completion yet.
+    pub fn is_parked(&self) -> bool {
+        !self.finished
+    }
+
+    /// If parked, checks whether this handler's predicate is already true
+    /// or its deadline (if any) has passed as of `now`.
+    pub fn poll(&self, now: Instant) -> Option<WaitResult> {
+        let wait = match self.wait {
+            Some(ref wait) => wait,
+            None => return None,
+        };
+
+        if let Some(ref predicate) = wait.predicate {
+            if predicate() {
+                return Some(WaitResult::Completed);
+            }
+        }
+
+        if let Some(deadline) = self.deadline {
+            if now >= deadline {
+                return Some(WaitResult::TimedOut);
+            }
+        }
+
+        None
+    }
+
+    /// Resumes the coroutine with `result`, running it until its next
+    /// `yield` or until it finishes.
+    pub fn resume(&mut self, result: WaitResult) {
+        if self.finished {
+            return;
+        }
+
+        let yielded = self.generator.resume(result);
+        self.absorb(yielded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn a_handler_that_never_yields_finishes_immediately() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran_in_body = ran.clone();
+
+        let handler = Handler::spawn(move |_yielder| {
+            ran_in_body.store(true, Ordering::SeqCst);
+        });
+
+        assert!(ran.load(Ordering::SeqCst));
+        assert!(!handler.is_parked());
+    }
+
+    #[test]
+    fn a_handler_parks_until_its_predicate_is_satisfied() {
+        let ready = Arc::new(AtomicBool::new(false));
+        let ready_in_body = ready.clone();
+        let finished = Arc::new(AtomicBool::new(false));
+        let finished_in_body = finished.clone();
+
+        let mut handler = Handler::spawn(move |yielder| {
+            let ready = ready_in_body.clone();
+            yielder.suspend(WaitRequest::until(move || ready.load(Ordering::SeqCst)));
+            finished_in_body.store(true, Ordering::SeqCst);
+        });
+
+        assert!(handler.is_parked());
+        assert_eq!(handler.poll(Instant::now()), None);
+
+        ready.store(true, Ordering::SeqCst);
+        assert_eq!(handler.poll(Instant::now()), Some(WaitResult::Completed));
+
+        handler.resume(WaitResult::Completed);
+        assert!(!handler.is_parked());
+        assert!(finished.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn a_sleeping_handler_times_out_once_the_deadline_passes() {
+        let mut handler = Handler::spawn(move |yielder| {
+            yielder.suspend(WaitRequest::sleep(Duration::from_millis(1)));
+        });
+
+        assert_eq!(handler.poll(Instant::now()), None);
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(handler.poll(Instant::now()), Some(WaitResult::TimedOut));
+
+        handler.resume(WaitResult::TimedOut);
+        assert!(!handler.is_parked());
+    }
+
+    #[test]
+    fn interrupting_a_parked_handler_unwinds_it_instead_of_resuming_the_wait() {
+        let result_seen = Arc::new(AtomicUsize::new(0));
+        let result_seen_in_body = result_seen.clone();
+
+        let mut handler = Handler::spawn(move |yielder| {
+            let result = yielder.suspend(WaitRequest::until(|| false));
+            result_seen_in_body.store(match result {
+                WaitResult::Interrupted => 1,
+                _ => 2,
+            }, Ordering::SeqCst);
+        });
+
+        assert!(handler.is_parked());
+
+        handler.resume(WaitResult::Interrupted);
+
+        assert!(!handler.is_parked());
+        assert_eq!(result_seen.load(Ordering::SeqCst), 1);
+    }
+}
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use std::sync::mpsc::Sender;
 use std::io::{self, Result, Error, ErrorKind};
 use std::fmt;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use mio::{Token, Ready, PollOpt};
 use mio::timer::{Timer, Builder};
@@ -23,10 +23,23 @@ use network::device;
 use reactors::api;
 use reactors::adapter::{Schedule, EndpointCollection, Network, SocketEventLoopContext};
 use reactors::sequence::Sequence;
+use reactors::coop::{Handler, WaitResult};
 
 const CHANNEL_TOKEN: Token = Token(::std::usize::MAX - 1);
 const BUS_TOKEN: Token = Token(::std::usize::MAX - 2);
 const TIMER_TOKEN: Token = Token(::std::usize::MAX - 3);
+const DRAIN_TOKEN: Token = Token(::std::usize::MAX - 6);
+
+// How long a shutdown triggered through `session::Request::Shutdown` (as
+// opposed to a caller-supplied deadline via `ShutdownGraceful`) waits for
+// pending sends to flush before closing everything anyway.
+const DEFAULT_DRAIN_DEADLINE_SECS: u64 = 5;
+// How often we re-check drain progress while waiting on the deadline.
+const DRAIN_POLL_INTERVAL_MS: u64 = 50;
+// Backoff before retrying a reconnect that `maintain_ideal_peers` kicks
+// off, so a peer that drops and immediately fails again doesn't get
+// hammered with zero-delay reconnect attempts on every close tick.
+const IDEAL_PEER_RECONNECT_BACKOFF_MS: u64 = 250;
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Scheduled(usize);
@@ -73,6 +86,17 @@ pub struct Dispatcher {
     sockets: session::Session,
     endpoints: EndpointCollection,
     schedule: Schedule,
+    // Handler threads parked on a `WaitRequest`, running the coroutine
+    // execution model alongside the `on_*` callback dispatch below.
+    parked: Vec<Handler>,
+    // `Some` once a graceful shutdown has been requested; cleared when
+    // the drain completes (or its deadline passes) and `run`'s loop is
+    // actually torn down.
+    draining: Option<Draining>,
+}
+
+struct Draining {
+    deadline: Instant,
 }
 
 impl Dispatcher {
@@ -105,8 +129,97 @@ impl Dispatcher {
             sockets: session::Session::new(id_seq.clone(), tx),
             endpoints: EndpointCollection::new(id_seq.clone(), transports),
             schedule: Schedule::new(timeout_eq),
+            parked: Vec::new(),
+            draining: None,
+        }
+
+    }
+
+    /// Does the actual work of a graceful shutdown, stopping every
+    /// acceptor from taking new connections and then keeping the loop
+    /// running only to flush each pipe's pending send queue (emitting
+    /// `Event::Sent` acks as they complete) until either every queue is
+    /// empty or `deadline` passes, whichever comes first. Reached either
+    /// from `process_session_request` (a `session::Request::Shutdown` /
+    /// `ShutdownGraceful` that arrived over `channel`, the path every
+    /// embedder actually uses) or from `process_signal`'s
+    /// `Signal::Shutdown` (the SIGINT handler, the one caller that has
+    /// only a `Send`-only `bus.notifier()` and no way to reach `channel`).
+    /// Both already have `el` in hand by the time they get here. Safe to
+    /// call more than once; later calls while already draining are
+    /// ignored.
+    fn begin_draining(&mut self, el: &mut EventLoop, deadline: Duration) {
+        if self.draining.is_some() {
+            return;
+        }
+
+        self.endpoints.pause_all_acceptors(el);
+        self.draining = Some(Draining { deadline: Instant::now() + deadline });
+
+        self.check_drain(el);
+    }
+
+    fn check_drain(&mut self, el: &mut EventLoop) {
+        let done = match self.draining {
+            Some(ref draining) => {
+                drain_is_done(Instant::now(), draining.deadline, self.endpoints.total_pending_sends())
+            }
+            None => return,
+        };
+
+        if done {
+            self.draining = None;
+            self.interrupt_parked();
+            self.endpoints.deregister_all(el);
+            el.shutdown();
+        } else {
+            // Mirrors mio's own deprecated `EventLoop::timeout_ms`: we
+            // just keep re-arming a short poll timeout rather than
+            // requiring the `Timer` to know about drain state directly.
+            let _ = el.timeout_ms(DRAIN_TOKEN, DRAIN_POLL_INTERVAL_MS);
         }
+    }
 
+    /// Parks a handler thread spawned via `reactors::coop::Handler::spawn`
+    /// so it gets re-evaluated every tick alongside callback dispatch.
+    pub fn park(&mut self, handler: Handler) {
+        if handler.is_parked() {
+            self.parked.push(handler);
+        }
+    }
+
+    /// Re-evaluates every parked handler's predicate/deadline and resumes
+    /// whichever ones are now satisfied. Called after `process_io` and
+    /// `process_timer` so a handler blocked in `socket::send`/`recv` wakes
+    /// up in the same tick that makes it runnable.
+    fn resume_parked(&mut self) {
+        let now = Instant::now();
+        let mut i = 0;
+
+        while i < self.parked.len() {
+            let outcome = self.parked[i].poll(now);
+
+            if let Some(result) = outcome {
+                self.parked[i].resume(result);
+            }
+
+            if self.parked[i].is_parked() {
+                i += 1;
+            } else {
+                self.parked.remove(i);
+            }
+        }
+    }
+
+    /// Resumes every parked handler with `Interrupted` so their stacks
+    /// unwind instead of being silently dropped mid-wait, then forgets
+    /// them. Called as part of tearing the dispatcher down.
+    fn interrupt_parked(&mut self) {
+        for handler in self.parked.iter_mut() {
+            handler.resume(WaitResult::Interrupted);
+        }
+
+        self.parked.clear();
     }
 
     pub fn run(&mut self) -> io::Result<()> {
@@ -155,6 +268,10 @@ impl Dispatcher {
             Signal::PipeEvt(sid, eid, evt) => self.process_pipe_evt(el, sid, eid, evt),
             Signal::AcceptorEvt(sid, eid, evt) => self.process_acceptor_evt(el, sid, eid, evt),
             Signal::SocketEvt(sid, evt) => self.process_socket_evt(el, sid, evt),
+            // Raised by the SIGINT handler installed through
+            // `reactors::signals::install`, which can only reach the
+            // dispatcher thread through this bus.
+            Signal::Shutdown(deadline) => self.begin_draining(el, deadline),
         }
     }
 
@@ -207,7 +324,10 @@ impl Dispatcher {
                 self.apply_on_socket(r, |socket, ctx| socket.on_device_plugged(ctx));
                 self.sockets.add_device(l, r);
             }
-            session::Request::Shutdown => el.shutdown(),
+            session::Request::Shutdown => {
+                self.begin_draining(el, Duration::from_secs(DEFAULT_DRAIN_DEADLINE_SECS))
+            }
+            session::Request::ShutdownGraceful(deadline) => self.begin_draining(el, deadline),
         }
     }
     fn process_socket_request(&mut self,
@@ -261,7 +381,7 @@ impl Dispatcher {
         }
     }
     fn process_pipe_evt(&mut self,
-                        _: &mut EventLoop,
+                        el: &mut EventLoop,
                         sid: SocketId,
                         eid: EndpointId,
                         evt: pipe::Event) {
@@ -273,7 +393,14 @@ impl Dispatcher {
                 self.apply_on_socket(sid, |socket, ctx| socket.on_send_ready(ctx, eid))
             }
             pipe::Event::Sent => {
-                self.apply_on_socket(sid, |socket, ctx| socket.on_send_ack(ctx, eid))
+                self.apply_on_socket(sid, |socket, ctx| socket.on_send_ack(ctx, eid));
+
+                // A send just completed: if we're draining, this may be
+                // the last thing on some pipe's queue, so recheck now
+                // instead of waiting for the next poll tick.
+                if self.draining.is_some() {
+                    self.check_drain(el);
+                }
             }
             pipe::Event::CanRecv => {
                 self.apply_on_socket(sid, |socket, ctx| socket.on_recv_ready(ctx, eid))
@@ -285,11 +412,23 @@ impl Dispatcher {
             pipe::Event::Error(err) => {
                 self.apply_on_socket(sid, |socket, ctx| socket.on_pipe_error(ctx, eid, err))
             }
-            pipe::Event::Closed => self.endpoints.remove_pipe(eid),
+            pipe::Event::Closed => {
+                let owner = self.endpoints.acceptor_of(eid);
+                self.endpoints.remove_pipe(eid);
+
+                // A slot just freed up: if the acceptor that produced this
+                // pipe had been paused because it was over its cap, let it
+                // start accepting again.
+                if let Some(aid) = owner {
+                    self.admit_from(el, sid, aid);
+                }
+
+                self.maintain_ideal_peers(sid, eid);
+            }
         }
     }
     fn process_acceptor_evt(&mut self,
-                            _: &mut EventLoop,
+                            el: &mut EventLoop,
                             sid: SocketId,
                             aid: EndpointId,
                             evt: pipe::Event) {
@@ -298,18 +437,85 @@ impl Dispatcher {
             pipe::Event::Error(e) => {
                 self.apply_on_socket(sid, |socket, ctx| socket.on_acceptor_error(ctx, aid, e))
             }
-            pipe::Event::Accepted(pipes) => {
+            pipe::Event::Accepted(mut pipes) => {
+                let cap = self.endpoints.max_pipes(aid);
+                let room = admission_room(pipes.len(), self.endpoints.pipe_count(aid), cap);
+                let surplus = pipes.split_off(::std::cmp::min(room, pipes.len()));
+
                 for pipe in pipes {
                     let pipe_id = self.endpoints.insert_pipe(sid, pipe);
 
                     self.apply_on_socket(sid,
                                          |socket, ctx| socket.on_pipe_accepted(ctx, aid, pipe_id));
                 }
+
+                for mut pipe in surplus {
+                    // Over the configured cap: reject outright rather than
+                    // handing a pipe the socket never asked for up to
+                    // `on_pipe_accepted`.
+                    pipe.close(el);
+                }
+
+                if at_or_over_cap(self.endpoints.pipe_count(aid), cap) {
+                    self.endpoints.pause_acceptor(el, aid);
+                }
             }
             _ => {}
         }
     }
 
+    /// Resumes `aid`'s readable interest once it has room for at least one
+    /// more pipe again. No-op if the acceptor has no cap or wasn't paused,
+    /// and no-op while draining: `begin_draining` paused every acceptor on
+    /// purpose to stop new connections, and a pipe closing mid-drain must
+    /// not silently undo that.
+    fn admit_from(&mut self, el: &mut EventLoop, _sid: SocketId, aid: EndpointId) {
+        if self.draining.is_some() {
+            return;
+        }
+
+        let has_room = self.endpoints
+                            .max_pipes(aid)
+                            .map_or(true, |cap| self.endpoints.pipe_count(aid) < cap);
+
+        if has_room {
+            self.endpoints.resume_acceptor(el, aid);
+        }
+    }
+
+    /// After a pipe closes, tops a connector endpoint back up to its ideal
+    /// peer target by scheduling (not issuing directly) a reconnect for
+    /// some other endpoint spec that's currently idle — the same
+    /// schedule-then-fire path every other reconnect in this dispatcher
+    /// goes through (see `process_socket_task`'s `Schedulable::Reconnect`
+    /// arm), so a peer that drops and immediately fails to reconnect gets
+    /// a backoff instead of being retried with zero delay on every
+    /// subsequent close tick. `eid` is the endpoint that just closed, so
+    /// it's excluded from the idle candidates even if it already shows up
+    /// as idle again by the time this runs.
+    fn maintain_ideal_peers(&mut self, sid: SocketId, eid: EndpointId) {
+        let target = match self.endpoints.ideal_peers(sid) {
+            Some(target) => target,
+            None => return,
+        };
+
+        if self.endpoints.live_peer_count(sid) >= target {
+            return;
+        }
+
+        let candidate = self.endpoints
+                             .idle_connectors(sid)
+                             .into_iter()
+                             .find(|&(idle_eid, _)| idle_eid != eid);
+
+        if let Some((idle_eid, spec)) = candidate {
+            self.apply_on_socket(sid, |_socket, ctx| {
+                let _ = ctx.schedule(Schedulable::Reconnect(idle_eid, spec),
+                                     Duration::from_millis(IDEAL_PEER_RECONNECT_BACKOFF_MS));
+            });
+        }
+    }
+
     fn process_socket_evt(&mut self, _: &mut EventLoop, sid: SocketId, evt: pipe::Event) {
         match evt {
             pipe::Event::Opened => {}
@@ -356,18 +562,126 @@ impl Dispatcher {
     }
 }
 
+/// How many of a freshly-`Accepted` batch fit under `cap` given `current`
+/// pipes already open on the same acceptor; the rest are surplus and get
+/// rejected outright by `process_acceptor_evt`. No cap means unlimited
+/// room. Pulled out of `process_acceptor_evt` so the split itself is
+/// testable without a real `EndpointCollection`.
+fn admission_room(accepted: usize, current: usize, cap: Option<usize>) -> usize {
+    cap.map(|cap| ::std::cmp::min(cap.saturating_sub(current), accepted)).unwrap_or(accepted)
+}
+
+/// Whether an acceptor that now has `count_after` pipes open should be
+/// paused because it's at (or over, if a batch landed it past the edge)
+/// its cap. No cap never pauses.
+fn at_or_over_cap(count_after: usize, cap: Option<usize>) -> bool {
+    cap.map_or(false, |cap| count_after >= cap)
+}
+
+/// Whether a drain begun against `deadline` should finish as of `now`,
+/// given `pending_sends` pipes still have something queued: either
+/// everything has flushed, or the deadline passed first and `check_drain`
+/// gives up waiting regardless. Pulled out of `check_drain` so the
+/// two-phase drain's actual stop condition is testable without a real
+/// `EndpointCollection`.
+fn drain_is_done(now: Instant, deadline: Instant, pending_sends: usize) -> bool {
+    now >= deadline || pending_sends == 0
+}
+
 impl EventHandler for Dispatcher {
     fn handle(&mut self, el: &mut EventLoop, token: Token, events: Ready) {
         if token == CHANNEL_TOKEN {
-            return self.process_channel(el);
+            self.process_channel(el);
+            return self.resume_parked();
         }
         if token == BUS_TOKEN {
-            return self.process_bus(el);
+            self.process_bus(el);
+            return self.resume_parked();
         }
         if token == TIMER_TOKEN {
-            return self.process_timer(el);
+            self.process_timer(el);
+            return self.resume_parked();
+        }
+
+        self.process_io(el, token, events);
+        self.resume_parked();
+    }
+
+    fn timeout(&mut self, el: &mut EventLoop, token: Token) {
+        if token == DRAIN_TOKEN {
+            self.check_drain(el);
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_uncapped_acceptor_has_room_for_the_whole_batch() {
+        assert_eq!(admission_room(5, 3, None), 5);
+    }
+
+    #[test]
+    fn a_capped_acceptor_only_has_room_for_what_s_left() {
+        assert_eq!(admission_room(5, 3, Some(6)), 3);
+    }
+
+    #[test]
+    fn an_already_full_acceptor_has_no_room_left() {
+        assert_eq!(admission_room(5, 6, Some(6)), 0);
+    }
+
+    #[test]
+    fn room_never_goes_negative_even_if_current_already_exceeds_cap() {
+        // Shouldn't happen in practice, but a cap lowered out from under
+        // an already-open batch must saturate at zero rather than
+        // wrapping (this is exactly what `saturating_sub` guards).
+        assert_eq!(admission_room(5, 10, Some(6)), 0);
+    }
+
+    #[test]
+    fn an_uncapped_acceptor_never_pauses() {
+        assert!(!at_or_over_cap(1_000, None));
+    }
+
+    #[test]
+    fn an_acceptor_under_its_cap_does_not_pause() {
+        assert!(!at_or_over_cap(5, Some(6)));
+    }
+
+    #[test]
+    fn an_acceptor_exactly_at_its_cap_pauses() {
+        assert!(at_or_over_cap(6, Some(6)));
+    }
+
+    #[test]
+    fn an_acceptor_pushed_past_its_cap_still_pauses() {
+        assert!(at_or_over_cap(7, Some(6)));
+    }
+
+    #[test]
+    fn a_drain_with_sends_still_pending_and_time_left_is_not_done() {
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(5);
+
+        assert!(!drain_is_done(now, deadline, 3));
+    }
+
+    #[test]
+    fn a_drain_finishes_once_every_pending_send_has_flushed() {
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(5);
+
+        assert!(drain_is_done(now, deadline, 0));
+    }
+
+    #[test]
+    fn a_drain_finishes_once_its_deadline_passes_even_with_sends_still_pending() {
+        let deadline = Instant::now();
+        let now = deadline + Duration::from_millis(1);
 
-        self.process_io(el, token, events)
+        assert!(drain_is_done(now, deadline, 3));
     }
 }
@@ -1,29 +1,85 @@
 use std::io;
+use std::time::Duration;
+
 use mio::{Poll, Token, Ready, Events, Evented, PollOpt};
+use mio::timer::{self, Builder};
+
+// Reserved so embedders registering their own `Evented`s can't collide
+// with the loop's own timer wheel.
+const TIMER_TOKEN: Token = Token(::std::usize::MAX - 4);
 
 pub trait EventHandler {
     fn handle(&mut self, el: &mut EventLoop, token: Token, events: Ready);
+
+    /// Invoked when a timeout registered through `EventLoop::timeout_ms`
+    /// fires. Default no-op so handlers that only care about IO don't
+    /// have to implement it.
+    fn timeout(&mut self, _el: &mut EventLoop, _token: Token) {}
 }
 
+/// A still-pending timeout, returned by `timeout_ms` and accepted by
+/// `clear_timeout`.
+#[derive(Clone)]
+pub struct TimeoutHandle(timer::Timeout);
+
 pub struct EventLoop {
     events_poller: Poll,
     events: Events,
-    running: bool
+    running: bool,
+    timer: timer::Timer<Token>,
+    max_poll_timeout: Option<Duration>,
 }
 
 impl EventLoop {
     pub fn new() -> io::Result<EventLoop> {
+        EventLoop::configured(Builder::default())
+    }
+
+    /// Builds a loop with a caller-tuned timer wheel (tick duration, slot
+    /// count, capacity), for embedders who need finer control than the
+    /// defaults over how many concurrent timeouts they can register.
+    pub fn configured(builder: Builder) -> io::Result<EventLoop> {
         let evts = Events::with_capacity(1024);
         let poll = try!(Poll::new());
+        let timer = builder.build();
+
+        try!(poll.register(&timer, TIMER_TOKEN, Ready::readable(), PollOpt::edge()));
+
         let event_loop = EventLoop {
             events_poller: poll,
             events: evts,
-            running: false
+            running: false,
+            timer: timer,
+            max_poll_timeout: None,
         };
 
         Ok(event_loop)
     }
 
+    /// Caps how long a single `poll` call may block even with no IO or
+    /// timeout pending, so embedders can drive their own periodic work
+    /// (via `run_once`) on the same thread. `None` (the default) blocks
+    /// until the next event, same as before this existed.
+    pub fn set_max_poll_timeout(&mut self, timeout: Option<Duration>) {
+        self.max_poll_timeout = timeout;
+    }
+
+    /// Schedules `token` to be delivered to `EventHandler::timeout` after
+    /// `delay_ms` milliseconds.
+    pub fn timeout_ms(&mut self, token: Token, delay_ms: u64) -> io::Result<TimeoutHandle> {
+        self.timer
+            .set_timeout(Duration::from_millis(delay_ms), token)
+            .map(TimeoutHandle)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "too many pending timeouts"))
+    }
+
+    /// Cancels a pending timeout. Returns `true` if it was still pending
+    /// (and therefore won't fire), `false` if it already fired or was
+    /// already cancelled.
+    pub fn clear_timeout(&mut self, handle: TimeoutHandle) -> bool {
+        self.timer.cancel_timeout(&handle.0).is_some()
+    }
+
     pub fn shutdown(&mut self) {
         self.running = false;
     }
@@ -56,7 +112,7 @@ impl EventLoop {
     }
 
     fn poll_events(&mut self) -> io::Result<usize> {
-        self.events_poller.poll(&mut self.events, None)
+        self.events_poller.poll(&mut self.events, self.max_poll_timeout)
     }
 
     fn process_events<H: EventHandler>(&mut self, event_handler: &mut H, count: usize) {
@@ -65,12 +121,22 @@ impl EventLoop {
         while i < count {
             let event = self.events.get(i).unwrap();
 
-            event_handler.handle(self, event.token(), event.kind());
+            if event.token() == TIMER_TOKEN {
+                self.fire_timeouts(event_handler);
+            } else {
+                event_handler.handle(self, event.token(), event.kind());
+            }
 
             i += 1;
         }
     }
 
+    fn fire_timeouts<H: EventHandler>(&mut self, event_handler: &mut H) {
+        while let Some(token) = self.timer.poll() {
+            event_handler.timeout(self, token);
+        }
+    }
+
     pub fn register(&mut self, io: &Evented, token: Token, interest: Ready, opt: PollOpt) -> io::Result<()> {
         self.events_poller.register(io, token, interest, opt)
     }
@@ -81,3 +147,62 @@ impl EventLoop {
         self.events_poller.deregister(io)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    struct RecordingHandler {
+        timeouts: Vec<Token>,
+    }
+
+    impl EventHandler for RecordingHandler {
+        fn handle(&mut self, _el: &mut EventLoop, _token: Token, _events: Ready) {}
+
+        fn timeout(&mut self, _el: &mut EventLoop, token: Token) {
+            self.timeouts.push(token);
+        }
+    }
+
+    #[test]
+    fn a_scheduled_timeout_fires_after_its_delay() {
+        let mut el = EventLoop::new().unwrap();
+        let mut handler = RecordingHandler { timeouts: Vec::new() };
+
+        el.timeout_ms(Token(1), 10).unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        el.run_once(&mut handler).unwrap();
+
+        assert_eq!(handler.timeouts, vec![Token(1)]);
+    }
+
+    #[test]
+    fn clearing_a_pending_timeout_stops_it_from_firing() {
+        let mut el = EventLoop::new().unwrap();
+        let mut handler = RecordingHandler { timeouts: Vec::new() };
+
+        let handle = el.timeout_ms(Token(2), 10).unwrap();
+        assert!(el.clear_timeout(handle));
+
+        thread::sleep(Duration::from_millis(50));
+        el.set_max_poll_timeout(Some(Duration::from_millis(10)));
+        el.run_once(&mut handler).unwrap();
+
+        assert!(handler.timeouts.is_empty());
+    }
+
+    #[test]
+    fn clearing_an_already_fired_timeout_returns_false() {
+        let mut el = EventLoop::new().unwrap();
+        let mut handler = RecordingHandler { timeouts: Vec::new() };
+
+        let handle = el.timeout_ms(Token(3), 10).unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        el.run_once(&mut handler).unwrap();
+
+        assert!(!el.clear_timeout(handle));
+    }
+}
@@ -0,0 +1,29 @@
+//! Wires SIGINT into the dispatcher's existing `EventLoopBus`, the same
+//! way `ctrlc` is wired into OpenEthereum's main loop: the signal handler
+//! itself runs on its own OS thread (signal-safety rules forbid doing
+//! anything interesting from inside the handler proper), so all it can
+//! safely do is hand a message to something thread-safe and return. Here
+//! that's a `Signal::Shutdown` pushed onto the same bus `Dispatcher`
+//! already polls for `PipeEvt`/`AcceptorEvt` and friends.
+
+use std::time::Duration;
+
+use ctrlc;
+
+use reactors::api::Signal;
+use reactors::bus::EventLoopBus;
+
+/// How long a Ctrl-C triggered shutdown drains pending sends before
+/// giving up and closing the remaining pipes anyway.
+const SIGINT_DRAIN_DEADLINE_SECS: u64 = 3;
+
+/// Installs a process-wide SIGINT handler that asks `bus` to raise a
+/// graceful `Signal::Shutdown`. Safe to call once per process; like
+/// `ctrlc::set_handler` itself, a second call replaces the first.
+pub fn install(bus: &EventLoopBus<Signal>) -> Result<(), ctrlc::Error> {
+    let notifier = bus.notifier();
+
+    ctrlc::set_handler(move || {
+        notifier.notify(Signal::Shutdown(Duration::from_secs(SIGINT_DRAIN_DEADLINE_SECS)));
+    })
+}